@@ -123,10 +123,41 @@ impl IppAttribute {
     }
 }
 
+/// A single delimited group of attributes, as it appeared on the wire.
+///
+/// IPP responses such as Get-Jobs emit one `job-attributes` group per job, each
+/// introduced by its own delimiter tag. Keeping groups separate (rather than
+/// merging everything that shares a tag into one map) preserves that structure.
+#[derive(Clone, Debug)]
+pub struct IppAttributeGroup {
+    tag: DelimiterTag,
+    attributes: HashMap<String, IppAttribute>,
+}
+
+impl IppAttributeGroup {
+    /// Create a new, empty group delimited by `tag`
+    pub fn new(tag: DelimiterTag) -> IppAttributeGroup {
+        IppAttributeGroup {
+            tag,
+            attributes: HashMap::new(),
+        }
+    }
+
+    /// Return the delimiter tag of this group
+    pub fn tag(&self) -> DelimiterTag {
+        self.tag
+    }
+
+    /// Return the attributes of this group
+    pub fn attributes(&self) -> &HashMap<String, IppAttribute> {
+        &self.attributes
+    }
+}
+
 /// Attribute list indexed by group and name
 #[derive(Clone, Default, Debug)]
 pub struct IppAttributeList {
-    attributes: HashMap<DelimiterTag, HashMap<String, IppAttribute>>,
+    groups: Vec<IppAttributeGroup>,
 }
 
 impl IppAttributeList {
@@ -135,46 +166,67 @@ impl IppAttributeList {
         IppAttributeList::default()
     }
 
+    /// Start a new attribute group delimited by `tag`, even if the previous
+    /// group in the list shares the same tag
+    pub fn begin_group(&mut self, tag: DelimiterTag) {
+        self.groups.push(IppAttributeGroup::new(tag));
+    }
+
     /// Add attribute to the list
     ///
     /// * `group` - delimiter group<br/>
     /// * `attribute` - attribute to add<br/>
+    ///
+    /// Appends to the current group if its tag matches `group`, otherwise starts a new one.
     pub fn add(&mut self, group: DelimiterTag, attribute: IppAttribute) {
-        self.attributes.entry(group).or_insert_with(HashMap::new);
-        let opt = self.attributes.get_mut(&group).unwrap();
-        opt.insert(attribute.name().to_string(), attribute);
+        if self.groups.last().map(|g| g.tag) != Some(group) {
+            self.begin_group(group);
+        }
+        self.groups
+            .last_mut()
+            .unwrap()
+            .attributes
+            .insert(attribute.name().to_string(), attribute);
     }
 
-    /// Get attribute from the list
+    /// Get attribute from the first group matching `group`
     pub fn get(&self, group: DelimiterTag, name: &str) -> Option<&IppAttribute> {
-        self.attributes
-            .get(&group)
-            .and_then(|attrs| attrs.get(name))
+        self.get_group(group).and_then(|attrs| attrs.get(name))
     }
 
-    /// Get attribute list for a group
+    /// Get attribute map for the first group matching `group`, for backward compatibility
     pub fn get_group(&self, group: DelimiterTag) -> Option<&HashMap<String, IppAttribute>> {
-        self.attributes.get(&group)
+        self.groups_of(group).next().map(|g| g.attributes())
     }
 
-    /// Get printer attributes
+    /// Iterate over all groups, in the order they were parsed or added
+    pub fn groups(&self) -> impl Iterator<Item = &IppAttributeGroup> {
+        self.groups.iter()
+    }
+
+    /// Iterate over all groups matching `tag`, in original order
+    pub fn groups_of(&self, tag: DelimiterTag) -> impl Iterator<Item = &IppAttributeGroup> {
+        self.groups.iter().filter(move |g| g.tag == tag)
+    }
+
+    /// Get printer attributes of the first printer-attributes group
     pub fn get_printer_attributes(&self) -> Option<&HashMap<String, IppAttribute>> {
         self.get_group(DelimiterTag::PrinterAttributes)
     }
 
-    /// Get job attributes
+    /// Get job attributes of the first job-attributes group
     pub fn get_job_attributes(&self) -> Option<&HashMap<String, IppAttribute>> {
         self.get_group(DelimiterTag::JobAttributes)
     }
 
-    /// Get operation attributes
+    /// Get operation attributes of the first operation-attributes group
     pub fn get_operation_attributes(&self) -> Option<&HashMap<String, IppAttribute>> {
         self.get_group(DelimiterTag::OperationAttributes)
     }
 
     /// Serialize attribute list into binary stream
     pub fn write(&self, writer: &mut Write) -> io::Result<usize> {
-        // first send the header attributes
+        // first send the header attributes, taken from the first operation-attributes group
         writer.write_u8(DelimiterTag::OperationAttributes as u8)?;
 
         let mut retval = 1;
@@ -185,23 +237,21 @@ impl IppAttributeList {
             }
         }
 
-        // now the rest
-        for hdr in &[
-            DelimiterTag::OperationAttributes,
-            DelimiterTag::JobAttributes,
-            DelimiterTag::PrinterAttributes,
-        ] {
-            let group = *hdr;
-            if let Some(attrs) = self.attributes.get(&group) {
-                if group != DelimiterTag::OperationAttributes {
-                    writer.write_u8(group as u8)?;
-                    retval += 1;
-                }
-                for (_, attr) in attrs.iter().filter(|&(_, v)| {
-                    group != DelimiterTag::OperationAttributes || !is_header_attr(v.name())
-                }) {
-                    retval += attr.write(writer)?;
-                }
+        // now the rest, group by group, in original order
+        let mut wrote_first_operation_group = false;
+        for group in &self.groups {
+            let is_first_operation_group =
+                group.tag == DelimiterTag::OperationAttributes && !wrote_first_operation_group;
+            if is_first_operation_group {
+                wrote_first_operation_group = true;
+            } else {
+                writer.write_u8(group.tag as u8)?;
+                retval += 1;
+            }
+            for (_, attr) in group.attributes.iter().filter(|&(_, v)| {
+                !is_first_operation_group || !is_header_attr(v.name())
+            }) {
+                retval += attr.write(writer)?;
             }
         }
         writer.write_u8(DelimiterTag::EndOfAttributes as u8)?;
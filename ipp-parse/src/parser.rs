@@ -6,6 +6,13 @@ use std::io::{self, Read};
 use byteorder::{BigEndian, ReadBytesExt};
 use log::debug;
 use num_traits::FromPrimitive;
+use thiserror::Error;
+
+#[cfg(feature = "async")]
+use std::io::Cursor;
+
+#[cfg(feature = "async")]
+use futures::io::{AsyncRead, AsyncReadExt};
 
 use attribute::{IppAttribute, IppAttributeList};
 use ipp::*;
@@ -20,6 +27,22 @@ fn list_to_value(mut list: Vec<IppValue>) -> IppValue {
     }
 }
 
+/// Errors which can occur while parsing an IPP stream
+#[derive(Debug, Error)]
+pub enum IppParseError {
+    /// Byte read from the stream is neither a known delimiter nor a value tag
+    #[error("invalid tag: {0:#x}")]
+    InvalidTag(u8),
+    /// Unbalanced collection tags: `EndCollection` with no matching `BegCollection`,
+    /// a `BegCollection` still open at `EndOfAttributes`, or `memberAttrName`
+    /// outside of any collection
+    #[error("invalid collection: unbalanced BegCollection/EndCollection or memberAttrName outside a collection")]
+    InvalidCollection,
+    /// Underlying I/O error while reading the stream
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
 /// IPP parsing result
 pub struct IppParseResult {
     pub header: IppHeader,
@@ -32,6 +55,132 @@ impl IppParseResult {
     }
 }
 
+/// Bookkeeping for the IPP attribute state machine, shared between the sync and async
+/// parsing loops so the two don't duplicate the stack/collection handling.
+struct ParserState {
+    // last delimiter tag
+    delimiter: DelimiterTag,
+    // stack of current attributes context. Used with lists and collections
+    stack: Vec<Vec<IppValue>>,
+    // holds the result of parsing
+    retval: IppAttributeList,
+    // name of previous attribute name
+    last_name: Option<String>,
+    // number of BegCollection tags not yet matched by an EndCollection
+    collection_depth: usize,
+}
+
+impl ParserState {
+    fn new() -> ParserState {
+        ParserState {
+            delimiter: DelimiterTag::EndOfAttributes,
+            stack: vec![vec![]],
+            retval: IppAttributeList::new(),
+            last_name: None,
+            collection_depth: 0,
+        }
+    }
+
+    /// Handle a delimiter tag. Returns `true` when this was `EndOfAttributes` and parsing is done.
+    fn delimiter_tag(&mut self, tag: u8) -> Result<bool, IppParseError> {
+        debug!("Delimiter tag: {:0x}", tag);
+        if tag == DelimiterTag::EndOfAttributes as u8 {
+            // exactly the initial frame should remain before we flush the last pending
+            // attribute; anything else means an unbalanced BegCollection/EndCollection
+            // left the stack inconsistent
+            if self.stack.len() != 1 || self.collection_depth != 0 {
+                return Err(IppParseError::InvalidCollection);
+            }
+            // end of stream, get last saved collection
+            if let Some(last_name) = self.last_name.take() {
+                if let Some(val_list) = self.stack.pop() {
+                    self.retval.add(
+                        self.delimiter,
+                        IppAttribute::new(&last_name, list_to_value(val_list)),
+                    );
+                }
+            }
+            Ok(true)
+        } else {
+            // flush the attribute still pending from the group we're leaving —
+            // otherwise it gets attributed to the next group under the new delimiter
+            if let Some(last_name) = self.last_name.take() {
+                if let Some(val_list) = self.stack.pop() {
+                    self.retval.add(
+                        self.delimiter,
+                        IppAttribute::new(&last_name, list_to_value(val_list)),
+                    );
+                }
+                self.stack.push(vec![]);
+            }
+
+            // remember delimiter tag and start a new group, even if the
+            // previous group shared the same tag (e.g. repeated job-attributes)
+            self.delimiter =
+                DelimiterTag::from_u8(tag).ok_or_else(|| IppParseError::InvalidTag(tag))?;
+            self.retval.begin_group(self.delimiter);
+            Ok(false)
+        }
+    }
+
+    /// Handle a value tag, given the attribute name length, name and already-parsed value.
+    fn value_tag(
+        &mut self,
+        tag: u8,
+        namelen: u16,
+        name: String,
+        value: IppValue,
+    ) -> Result<(), IppParseError> {
+        debug!("Value tag: {:0x}: {}: {}", tag, name, value);
+
+        if tag == ValueTag::MemberAttrName as u8 && self.collection_depth == 0 {
+            // memberAttrName only makes sense inside a BegCollection/EndCollection pair
+            return Err(IppParseError::InvalidCollection);
+        }
+
+        if namelen > 0 {
+            // single attribute or begin of array
+            if let Some(last_name) = self.last_name.take() {
+                // put the previous attribute into the retval
+                if let Some(val_list) = self.stack.pop() {
+                    self.retval.add(
+                        self.delimiter,
+                        IppAttribute::new(&last_name, list_to_value(val_list)),
+                    );
+                }
+                self.stack.push(vec![]);
+            }
+            // store it as a previous attribute
+            self.last_name = Some(name);
+        }
+        if tag == ValueTag::BegCollection as u8 {
+            // start new collection in the stack
+            debug!("Begin collection");
+            self.collection_depth += 1;
+            self.stack.push(vec![])
+        } else if tag == ValueTag::EndCollection as u8 {
+            // get collection from the stack and add it to the previous element
+            debug!("End collection");
+            if self.collection_depth == 0 {
+                return Err(IppParseError::InvalidCollection);
+            }
+            let arr = self.stack.pop().ok_or(IppParseError::InvalidCollection)?;
+            self.collection_depth -= 1;
+            if let Some(val_list) = self.stack.last_mut() {
+                val_list.push(IppValue::Collection(arr));
+            }
+        } else if let Some(val_list) = self.stack.last_mut() {
+            // add attribute to the current collection
+            val_list.push(value);
+        }
+        Ok(())
+    }
+
+    fn finish(self, header: IppHeader) -> IppParseResult {
+        IppParseResult::new(header, self.retval)
+    }
+}
+
 /// IPP parser implementation
 pub struct IppParser<'a> {
     reader: &'a mut Read,
@@ -44,18 +193,8 @@ impl<'a> IppParser<'a> {
     }
 
     /// Parse IPP stream
-    pub fn parse(&mut self) -> io::Result<IppParseResult> {
-        // last delimiter tag
-        let mut delimiter = DelimiterTag::EndOfAttributes;
-
-        // stack of current attributes context. Used with lists and collections
-        let mut stack = vec![vec![]];
-
-        // holds the result of parsing
-        let mut retval = IppAttributeList::new();
-
-        // name of previous attribute name
-        let mut last_name: Option<String> = None;
+    pub fn parse(&mut self) -> Result<IppParseResult, IppParseError> {
+        let mut state = ParserState::new();
 
         // parse IPP header
         let header = IppHeader::from_reader(self.reader)?;
@@ -64,72 +203,242 @@ impl<'a> IppParser<'a> {
         loop {
             let tag = self.reader.read_u8()?;
             if is_delimiter_tag(tag) {
-                debug!("Delimiter tag: {:0x}", tag);
-                if tag == DelimiterTag::EndOfAttributes as u8 {
-                    // end of stream, get last saved collection
-                    if let Some(last_name) = last_name {
-                        if let Some(val_list) = stack.pop() {
-                            retval.add(
-                                delimiter,
-                                IppAttribute::new(&last_name, list_to_value(val_list)),
-                            );
-                        }
-                    }
+                if state.delimiter_tag(tag)? {
                     break;
-                } else {
-                    // remember delimiter tag
-                    delimiter = DelimiterTag::from_u8(tag).ok_or(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Tag error: {}", tag),
-                    ))?;
                 }
             } else if is_value_tag(tag) {
-                // value tag
                 let namelen = self.reader.read_u16::<BigEndian>()?;
                 let name = self.reader.read_string(namelen as usize)?;
                 let value = IppValue::read(tag, &mut self.reader)?;
+                state.value_tag(tag, namelen, name, value)?;
+            } else {
+                return Err(IppParseError::InvalidTag(tag));
+            }
+        }
+
+        Ok(state.finish(header))
+    }
+}
+
+/// Async wrapper around a futures `AsyncRead`, mirroring `IppReadExt` for the async parser.
+#[cfg(feature = "async")]
+pub struct AsyncIppReader<R> {
+    inner: R,
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin> AsyncIppReader<R> {
+    /// Create a new async IPP reader wrapping `inner`
+    pub fn new(inner: R) -> AsyncIppReader<R> {
+        AsyncIppReader { inner }
+    }
+
+    /// Read a single byte
+    pub async fn read_u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.inner.read_exact(&mut buf).await?;
+        Ok(buf[0])
+    }
+
+    /// Read a big-endian u16
+    pub async fn read_u16(&mut self) -> io::Result<u16> {
+        let mut buf = [0u8; 2];
+        self.inner.read_exact(&mut buf).await?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Read a string of the given length
+    pub async fn read_string(&mut self, len: usize) -> io::Result<String> {
+        let buf = self.read_raw(len).await?;
+        String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
 
-                debug!("Value tag: {:0x}: {}: {}", tag, name, value);
-
-                if namelen > 0 {
-                    // single attribute or begin of array
-                    if let Some(last_name) = last_name {
-                        // put the previous attribute into the retval
-                        if let Some(val_list) = stack.pop() {
-                            retval.add(
-                                delimiter,
-                                IppAttribute::new(&last_name, list_to_value(val_list)),
-                            );
-                        }
-                        stack.push(vec![]);
-                    }
-                    // store it as a previous attribute
-                    last_name = Some(name);
+    /// Read `len` raw bytes
+    async fn read_raw(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.inner.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+}
+
+#[cfg(feature = "async")]
+impl IppHeader {
+    /// Read an IPP header asynchronously
+    pub async fn from_async_reader<R: AsyncRead + Unpin>(
+        reader: &mut AsyncIppReader<R>,
+    ) -> io::Result<IppHeader> {
+        let buf = reader.read_raw(8).await?;
+        IppHeader::from_reader(&mut Cursor::new(buf))
+    }
+}
+
+#[cfg(feature = "async")]
+impl IppValue {
+    /// Read an IPP value asynchronously
+    pub async fn read_async<R: AsyncRead + Unpin>(
+        tag: u8,
+        reader: &mut AsyncIppReader<R>,
+    ) -> io::Result<IppValue> {
+        let len = reader.read_u16().await?;
+        let data = reader.read_raw(len as usize).await?;
+
+        let mut buf = Vec::with_capacity(2 + data.len());
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&data);
+
+        IppValue::read(tag, &mut Cursor::new(buf))
+    }
+}
+
+/// Async (futures) IPP parser, driving the same state machine as `IppParser`
+#[cfg(feature = "async")]
+pub struct AsyncIppParser<R> {
+    reader: AsyncIppReader<R>,
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin> AsyncIppParser<R> {
+    /// Create an async IPP parser using the given `AsyncRead`
+    pub fn new(reader: R) -> AsyncIppParser<R> {
+        AsyncIppParser {
+            reader: AsyncIppReader::new(reader),
+        }
+    }
+
+    /// Parse IPP stream asynchronously
+    pub async fn parse(&mut self) -> Result<IppParseResult, IppParseError> {
+        let mut state = ParserState::new();
+
+        let header = IppHeader::from_async_reader(&mut self.reader).await?;
+        debug!("IPP reply header: {:?}", header);
+
+        loop {
+            let tag = self.reader.read_u8().await?;
+            if is_delimiter_tag(tag) {
+                if state.delimiter_tag(tag)? {
+                    break;
                 }
-                if tag == ValueTag::BegCollection as u8 {
-                    // start new collection in the stack
-                    debug!("Begin collection");
-                    stack.push(vec![])
-                } else if tag == ValueTag::EndCollection as u8 {
-                    // get collection from the stack and add it to the previous element
-                    debug!("End collection");
-                    if let Some(arr) = stack.pop() {
-                        if let Some(val_list) = stack.last_mut() {
-                            val_list.push(IppValue::Collection(arr));
-                        }
-                    }
-                } else if let Some(val_list) = stack.last_mut() {
-                    // add attribute to the current collection
-                    val_list.push(value);
+            } else if is_value_tag(tag) {
+                let namelen = self.reader.read_u16().await?;
+                let name = self.reader.read_string(namelen as usize).await?;
+                let value = IppValue::read_async(tag, &mut self.reader).await?;
+                state.value_tag(tag, namelen, name, value)?;
+            } else {
+                return Err(IppParseError::InvalidTag(tag));
+            }
+        }
+
+        Ok(state.finish(header))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Drive the attribute state machine over already-serialized bytes, without a
+    /// preceding IPP header, so group/collection round-trips can be tested in isolation.
+    fn parse_attributes(bytes: &[u8]) -> Result<IppAttributeList, IppParseError> {
+        let mut reader = Cursor::new(bytes);
+        let mut state = ParserState::new();
+        loop {
+            let tag = reader.read_u8()?;
+            if is_delimiter_tag(tag) {
+                if state.delimiter_tag(tag)? {
+                    break;
                 }
+            } else if is_value_tag(tag) {
+                let namelen = reader.read_u16::<BigEndian>()?;
+                let name = reader.read_string(namelen as usize)?;
+                let value = IppValue::read(tag, &mut reader)?;
+                state.value_tag(tag, namelen, name, value)?;
             } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Tag error: {}", tag),
-                ));
+                return Err(IppParseError::InvalidTag(tag));
             }
         }
+        Ok(state.retval)
+    }
+
+    #[test]
+    fn round_trips_nested_collections() {
+        let inner = IppValue::Collection(vec![IppValue::Integer(42)]);
+        let outer = IppValue::Collection(vec![inner, IppValue::Boolean(true)]);
+
+        let mut list = IppAttributeList::new();
+        list.add(
+            DelimiterTag::JobAttributes,
+            IppAttribute::new("media-col", outer.clone()),
+        );
+
+        let mut bytes = Vec::new();
+        list.write(&mut bytes).unwrap();
+
+        let parsed = parse_attributes(&bytes).unwrap();
+        let attr = parsed
+            .get(DelimiterTag::JobAttributes, "media-col")
+            .unwrap();
+        assert_eq!(*attr.value(), outer);
+    }
+
+    #[test]
+    fn rejects_end_collection_without_matching_begin() {
+        let mut bytes = vec![DelimiterTag::OperationAttributes as u8];
+        bytes.push(ValueTag::EndCollection as u8);
+        bytes.extend_from_slice(&[0, 0]); // zero-length name
+        bytes.extend_from_slice(&[0, 0]); // zero-length value
+        bytes.push(DelimiterTag::EndOfAttributes as u8);
+
+        let err = parse_attributes(&bytes).unwrap_err();
+        assert!(matches!(err, IppParseError::InvalidCollection));
+    }
+
+    #[test]
+    fn rejects_dangling_begin_collection_at_end_of_stream() {
+        let mut bytes = vec![DelimiterTag::OperationAttributes as u8];
+        bytes.push(ValueTag::BegCollection as u8);
+        bytes.extend_from_slice(&[0, 4]); // name length
+        bytes.extend_from_slice(b"col1");
+        bytes.extend_from_slice(&[0, 0]); // zero-length value
+        bytes.push(DelimiterTag::EndOfAttributes as u8);
+
+        let err = parse_attributes(&bytes).unwrap_err();
+        assert!(matches!(err, IppParseError::InvalidCollection));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_parser_round_trips_repeated_groups() {
+        use futures::executor::block_on;
+        use futures::io::Cursor as AsyncCursor;
+
+        let mut list = IppAttributeList::new();
+        list.add(
+            DelimiterTag::JobAttributes,
+            IppAttribute::new("job-id", IppValue::Integer(1)),
+        );
+        list.begin_group(DelimiterTag::JobAttributes);
+        list.add(
+            DelimiterTag::JobAttributes,
+            IppAttribute::new("job-id", IppValue::Integer(2)),
+        );
+
+        // 8-byte IPP header: version 1.1, status 0, request-id 1
+        let mut bytes = vec![1, 1, 0, 0, 0, 0, 0, 1];
+        list.write(&mut bytes).unwrap();
+
+        let result = block_on(AsyncIppParser::new(AsyncCursor::new(bytes)).parse()).unwrap();
+        let attr = result
+            .attributes
+            .get(DelimiterTag::JobAttributes, "job-id")
+            .unwrap();
+        assert_eq!(*attr.value(), IppValue::Integer(1));
 
-        Ok(IppParseResult::new(header, retval))
+        let ids: Vec<&IppValue> = result
+            .attributes
+            .groups_of(DelimiterTag::JobAttributes)
+            .map(|g| g.attributes()["job-id"].value())
+            .collect();
+        assert_eq!(ids, vec![&IppValue::Integer(1), &IppValue::Integer(2)]);
     }
 }